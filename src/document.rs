@@ -0,0 +1,125 @@
+use tower_lsp_server::lsp_types::{Position, Range};
+
+/// Whether client<->server positions are measured in UTF-8 bytes or UTF-16
+/// code units, as negotiated via `general.positionEncodings` during
+/// `initialize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OffsetEncoding {
+    Utf8,
+    #[default]
+    Utf16,
+}
+
+/// The authoritative contents of an open document, plus a line-start index
+/// so LSP positions can be turned into byte offsets by direct line lookup.
+/// The index is rebuilt from the whole text after every applied change.
+#[derive(Debug, Clone)]
+pub struct Document {
+    text: String,
+    line_starts: Vec<usize>,
+}
+
+impl Document {
+    pub fn new(text: String) -> Self {
+        let line_starts = line_starts(&text);
+        Document { text, line_starts }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Applies a single `textDocument/didChange` content change. `range:
+    /// None` replaces the whole document (full sync, or a client that
+    /// doesn't advertise incremental sync support).
+    pub fn apply_change(&mut self, range: Option<Range>, text: &str, encoding: OffsetEncoding) {
+        match range {
+            Some(range) => {
+                let start = self.position_to_offset(range.start, encoding);
+                let end = self.position_to_offset(range.end, encoding);
+                self.text.replace_range(start..end, text);
+            }
+            None => self.text = text.to_string(),
+        }
+        self.line_starts = line_starts(&self.text);
+    }
+
+    /// Converts an LSP `Position` to a byte offset into `self.text`.
+    fn position_to_offset(&self, position: Position, encoding: OffsetEncoding) -> usize {
+        let line_start = self
+            .line_starts
+            .get(position.line as usize)
+            .copied()
+            .unwrap_or(self.text.len());
+        let line_end = self
+            .line_starts
+            .get(position.line as usize + 1)
+            .copied()
+            .unwrap_or(self.text.len());
+        let line = &self.text[line_start..line_end.min(self.text.len())];
+
+        let column_bytes = match encoding {
+            OffsetEncoding::Utf8 => position.character as usize,
+            OffsetEncoding::Utf16 => {
+                let mut utf16_units = 0u32;
+                let mut byte_offset = line.len();
+                for (idx, ch) in line.char_indices() {
+                    if utf16_units >= position.character {
+                        byte_offset = idx;
+                        break;
+                    }
+                    utf16_units += ch.len_utf16() as u32;
+                }
+                byte_offset
+            }
+        };
+
+        (line_start + column_bytes).min(self.text.len())
+    }
+}
+
+/// Byte offsets of the start of every line in `text`.
+fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(idx, _)| idx + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_incremental_change_within_a_line() {
+        let mut document = Document::new("SELECT *\nFROM foo\n".to_string());
+        document.apply_change(
+            Some(Range::new(Position::new(1, 5), Position::new(1, 8))),
+            "bar",
+            OffsetEncoding::Utf16,
+        );
+        assert_eq!(document.text(), "SELECT *\nFROM bar\n");
+    }
+
+    #[test]
+    fn applies_change_spanning_multiple_lines() {
+        let mut document = Document::new("SELECT *\nFROM foo\nWHERE x = 1\n".to_string());
+        document.apply_change(
+            Some(Range::new(Position::new(0, 7), Position::new(1, 4))),
+            "1\nFROM",
+            OffsetEncoding::Utf16,
+        );
+        assert_eq!(document.text(), "SELECT 1\nFROM foo\nWHERE x = 1\n");
+    }
+
+    #[test]
+    fn measures_columns_in_utf16_code_units() {
+        // "日本" is 2 UTF-16 code units but 6 UTF-8 bytes.
+        let mut document = Document::new("SELECT '日本a'\n".to_string());
+        document.apply_change(
+            Some(Range::new(Position::new(0, 10), Position::new(0, 11))),
+            "b",
+            OffsetEncoding::Utf16,
+        );
+        assert_eq!(document.text(), "SELECT '日本b'\n");
+    }
+}
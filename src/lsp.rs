@@ -1,29 +1,66 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use tokio::sync::{RwLock, watch};
+use serde_json::Value;
+use tokio::sync::{OnceCell, RwLock, watch};
 use tower_lsp_server::{Client, LanguageServer, jsonrpc::Result, lsp_types::*};
 use tracing::error;
 
-use crate::sqlfluff;
+use crate::document::{Document, OffsetEncoding};
+use crate::sqlfluff::{Config, Worker};
+
+const RESTART_COMMAND: &str = "sqlfluff.restart";
+const CONFIG_WATCHER_ID: &str = "sqlfluff-config-watcher";
+const CONFIG_GLOB_PATTERN: &str = "**/.sqlfluff";
 
 #[derive(Debug)]
 pub struct Backend {
     client: Client,
-    config: Config,
+    state: Arc<SharedState>,
+    offset_encoding: OnceCell<OffsetEncoding>,
     watchers: RwLock<HashMap<Uri, Watcher>>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Config {
-    pub dialect: Option<String>,
-    pub templater: Option<String>,
-    pub sqlfluff_path: Option<String>,
+/// State shared with the background lint task spawned per open document, so
+/// a `sqlfluff.restart` or configuration change is picked up by tasks that
+/// were already running before it happened.
+#[derive(Debug)]
+struct SharedState {
+    config: RwLock<Config>,
+    worker: RwLock<Option<Arc<Worker>>>,
+}
+
+impl SharedState {
+    async fn worker(&self) -> Arc<Worker> {
+        if let Some(worker) = self.worker.read().await.clone() {
+            return worker;
+        }
+
+        let mut worker = self.worker.write().await;
+        if let Some(worker) = worker.clone() {
+            return worker;
+        }
+
+        let spawned = Worker::spawn(self.config.read().await.clone());
+        *worker = Some(spawned.clone());
+        spawned
+    }
+
+    /// Tears down the current worker (if any) and spawns a new one against
+    /// the latest `Config`.
+    async fn restart_worker(&self) {
+        let new_worker = Worker::spawn(self.config.read().await.clone());
+        let old_worker = self.worker.write().await.replace(new_worker);
+        if let Some(old_worker) = old_worker {
+            old_worker.shutdown().await;
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Watcher {
-    tx: watch::Sender<String>,
-    rx: watch::Receiver<String>,
+    tx: watch::Sender<Document>,
+    rx: watch::Receiver<Document>,
 }
 
 impl Backend {
@@ -35,35 +72,119 @@ impl Backend {
     ) -> Self {
         Self {
             client,
-            config: Config {
-                dialect,
-                templater,
-                sqlfluff_path,
-            },
+            state: Arc::new(SharedState {
+                config: RwLock::new(Config {
+                    dialect,
+                    templater,
+                    sqlfluff_path,
+                    ..Default::default()
+                }),
+                worker: RwLock::new(None),
+            }),
+            offset_encoding: OnceCell::new(),
             watchers: RwLock::new(HashMap::new()),
         }
     }
+
+    fn offset_encoding(&self) -> OffsetEncoding {
+        self.offset_encoding.get().copied().unwrap_or_default()
+    }
+
+    async fn relint_open_documents(&self) {
+        let worker = self.state.worker().await;
+        let overrides = self.state.config.read().await.rule_severity_overrides.clone();
+        let documents: Vec<(Uri, String)> = self
+            .watchers
+            .read()
+            .await
+            .iter()
+            .map(|(uri, watcher)| (uri.clone(), watcher.rx.borrow().text().to_string()))
+            .collect();
+
+        for (uri, content) in documents {
+            match worker.lint(&uri, &content, &overrides).await {
+                Ok(diags) => self.client.publish_diagnostics(uri, diags, None).await,
+                Err(error) => {
+                    error!("{error}");
+                    self.client.show_message(MessageType::ERROR, error).await;
+                }
+            }
+        }
+    }
 }
 
 impl LanguageServer for Backend {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        let supports_utf8 = params
+            .capabilities
+            .general
+            .as_ref()
+            .and_then(|general| general.position_encodings.as_ref())
+            .is_some_and(|encodings| encodings.contains(&PositionEncodingKind::UTF8));
+        let encoding = if supports_utf8 {
+            OffsetEncoding::Utf8
+        } else {
+            OffsetEncoding::Utf16
+        };
+        let _ = self.offset_encoding.set(encoding);
+
         Ok(InitializeResult {
             server_info: None,
             capabilities: ServerCapabilities {
+                position_encoding: Some(if encoding == OffsetEncoding::Utf8 {
+                    PositionEncodingKind::UTF8
+                } else {
+                    PositionEncodingKind::UTF16
+                }),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
                 document_formatting_provider: Some(OneOf::Right(DocumentFormattingOptions {
                     work_done_progress_options: WorkDoneProgressOptions {
                         work_done_progress: Some(false),
                     },
                 })),
+                code_action_provider: Some(CodeActionProviderCapability::Options(
+                    CodeActionOptions {
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::QUICKFIX,
+                            CodeActionKind::SOURCE_FIX_ALL,
+                        ]),
+                        work_done_progress_options: WorkDoneProgressOptions {
+                            work_done_progress: Some(false),
+                        },
+                        resolve_provider: Some(false),
+                    },
+                )),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![RESTART_COMMAND.to_string()],
+                    work_done_progress_options: WorkDoneProgressOptions {
+                        work_done_progress: Some(false),
+                    },
+                }),
                 ..ServerCapabilities::default()
             },
         })
     }
 
-    async fn initialized(&self, _: InitializedParams) {}
+    async fn initialized(&self, _: InitializedParams) {
+        self.state.worker().await;
+
+        let registration = Registration {
+            id: CONFIG_WATCHER_ID.to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![FileSystemWatcher {
+                    glob_pattern: GlobPattern::String(CONFIG_GLOB_PATTERN.to_string()),
+                    kind: None,
+                }],
+            })
+            .ok(),
+        };
+        if let Err(error) = self.client.register_capability(vec![registration]).await {
+            error!("failed to register `.sqlfluff` file watcher: {error}");
+        }
+    }
 
     async fn shutdown(&self) -> Result<()> {
         Ok(())
@@ -76,15 +197,14 @@ impl LanguageServer for Backend {
             ..
         }: DocumentFormattingParams,
     ) -> Result<Option<Vec<TextEdit>>> {
-        let config = self.config.clone();
         if let Some(content) = self
             .watchers
             .read()
             .await
             .get(&uri)
-            .map(|bar| bar.rx.borrow().clone())
+            .map(|watcher| watcher.rx.borrow().text().to_string())
         {
-            let output = match sqlfluff::fmt(&uri, &content, config).await {
+            let output = match self.state.worker().await.fmt(&uri, &content).await {
                 Ok(output) => output,
                 Err(error) => {
                     error!("{error}");
@@ -99,28 +219,158 @@ impl LanguageServer for Backend {
         }
     }
 
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let CodeActionParams {
+            text_document: TextDocumentIdentifier { uri },
+            context,
+            ..
+        } = params;
+
+        let Some(content) = self
+            .watchers
+            .read()
+            .await
+            .get(&uri)
+            .map(|watcher| watcher.rx.borrow().text().to_string())
+        else {
+            return Ok(None);
+        };
+
+        let worker = self.state.worker().await;
+        let mut actions = Vec::new();
+
+        for diagnostic in &context.diagnostics {
+            let Some(NumberOrString::String(code)) = &diagnostic.code else {
+                continue;
+            };
+
+            let edits = match worker.fix(&uri, &content, Some(code)).await {
+                Ok(edits) if !edits.is_empty() => edits,
+                Ok(_) => continue,
+                Err(error) => {
+                    error!("{error}");
+                    continue;
+                }
+            };
+
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Fix {code} violation"),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri.clone(), edits)])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }));
+        }
+
+        match worker.fix(&uri, &content, None).await {
+            Ok(edits) if !edits.is_empty() => {
+                actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                    title: "Fix all sqlfluff violations".to_string(),
+                    kind: Some(CodeActionKind::SOURCE_FIX_ALL),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(HashMap::from([(uri.clone(), edits)])),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }));
+            }
+            Ok(_) => {}
+            Err(error) => error!("{error}"),
+        }
+
+        Ok(Some(actions))
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        if params.command == RESTART_COMMAND {
+            self.state.restart_worker().await;
+            self.relint_open_documents().await;
+        }
+
+        Ok(None)
+    }
+
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        let Some(settings) = params.settings.as_object() else {
+            return;
+        };
+        let dialect = settings
+            .get("dialect")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let templater = settings
+            .get("templater")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let sqlfluff_path = settings
+            .get("sqlfluffPath")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let rule_severity_overrides: HashMap<String, DiagnosticSeverity> = settings
+            .get("ruleSeverityOverrides")
+            .and_then(Value::as_object)
+            .map(|overrides| {
+                overrides
+                    .iter()
+                    .filter_map(|(code, value)| {
+                        Some((code.clone(), parse_severity(value.as_str()?)?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let restart_needed = {
+            let mut config = self.state.config.write().await;
+            let restart_needed = config.dialect != dialect
+                || config.templater != templater
+                || config.sqlfluff_path != sqlfluff_path;
+            config.dialect = dialect;
+            config.templater = templater;
+            config.sqlfluff_path = sqlfluff_path;
+            config.rule_severity_overrides = rule_severity_overrides;
+            restart_needed
+        };
+
+        if restart_needed {
+            self.state.restart_worker().await;
+        }
+        self.relint_open_documents().await;
+    }
+
+    async fn did_change_watched_files(&self, _: DidChangeWatchedFilesParams) {
+        self.relint_open_documents().await;
+    }
+
     async fn did_open(
         &self,
         DidOpenTextDocumentParams {
             text_document: TextDocumentItem { uri, text, .. },
         }: DidOpenTextDocumentParams,
     ) {
-        let config = self.config.clone();
+        let state = self.state.clone();
         self.watchers
             .write()
             .await
             .entry(uri.clone())
-            .and_modify(|watcher| watcher.tx.send(text.clone()).unwrap())
+            .and_modify(|watcher| watcher.tx.send(Document::new(text.clone())).unwrap())
             .or_insert_with(|| {
-                let (tx, rx) = watch::channel(text);
+                let (tx, rx) = watch::channel(Document::new(text));
 
                 let client = self.client.clone();
                 let mut _rx = rx.clone();
                 tokio::spawn(async move {
                     loop {
-                        let content = _rx.borrow_and_update().clone();
+                        let content = _rx.borrow_and_update().text().to_string();
+                        let overrides =
+                            state.config.read().await.rule_severity_overrides.clone();
 
-                        match sqlfluff::lint(&uri, &content, config.clone()).await {
+                        match state.worker().await.lint(&uri, &content, &overrides).await {
                             Ok(diags) => {
                                 client.publish_diagnostics(uri.clone(), diags, None).await;
                             }
@@ -157,10 +407,14 @@ impl LanguageServer for Backend {
             content_changes,
         }: DidChangeTextDocumentParams,
     ) {
-        if let Some(change) = content_changes.first()
-            && let Some(watcher) = self.watchers.read().await.get(&uri)
-        {
-            watcher.tx.send(change.text.clone()).unwrap();
+        let encoding = self.offset_encoding();
+        let watchers = self.watchers.read().await;
+        if let Some(watcher) = watchers.get(&uri) {
+            let mut document = watcher.rx.borrow().clone();
+            for change in content_changes {
+                document.apply_change(change.range, &change.text, encoding);
+            }
+            watcher.tx.send(document).unwrap();
         }
     }
 
@@ -174,7 +428,19 @@ impl LanguageServer for Backend {
         if let Some(text) = text
             && let Some(watcher) = self.watchers.read().await.get(&uri)
         {
-            watcher.tx.send(text).unwrap();
+            watcher.tx.send(Document::new(text)).unwrap();
         }
     }
 }
+
+/// Parses a `ruleSeverityOverrides` setting value (`"error"`, `"warning"`,
+/// `"information"`/`"info"`, `"hint"`) into a [`DiagnosticSeverity`].
+fn parse_severity(value: &str) -> Option<DiagnosticSeverity> {
+    match value.to_ascii_lowercase().as_str() {
+        "error" => Some(DiagnosticSeverity::ERROR),
+        "warning" => Some(DiagnosticSeverity::WARNING),
+        "information" | "info" => Some(DiagnosticSeverity::INFORMATION),
+        "hint" => Some(DiagnosticSeverity::HINT),
+        _ => None,
+    }
+}
@@ -1,10 +1,30 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 
 use anyhow::Context as _;
-use serde::Deserialize;
-use tokio::io::{self, AsyncWriteExt};
-use tokio::process::Command;
-use tower_lsp_server::lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range, TextEdit, Uri};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::{Mutex, oneshot};
+use tower_lsp_server::lsp_types::{
+    Diagnostic, DiagnosticSeverity, DiagnosticTag, NumberOrString, Position, Range, TextEdit, Uri,
+};
+use tracing::error;
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub dialect: Option<String>,
+    pub templater: Option<String>,
+    pub sqlfluff_path: Option<String>,
+    /// Per rule-code overrides (e.g. promoting `LT01` from `INFORMATION` to
+    /// `WARNING`), layered on top of [`default_severity`]'s rule-group
+    /// mapping.
+    pub rule_severity_overrides: HashMap<String, DiagnosticSeverity>,
+}
 
 #[derive(Deserialize, Debug)]
 struct LintOutput {
@@ -15,136 +35,492 @@ struct LintOutput {
     message: String,
 }
 
-pub async fn lint(
-    uri: &Uri,
-    content: &str,
-    dialect: Option<String>,
-) -> anyhow::Result<Vec<Diagnostic>> {
-    let output = Sqlfluff::new("lint")
-        .dialect(dialect)
-        .args(&[
-            &format!("--stdin-filename={}", uri.path()),
-            "--disable-progress-bar",
-            "--nocolor",
-            "--format=github-annotation",
-            "--nofail",
-            "-",
-        ])
-        .execute(content)
-        .await?;
-
-    if output.status.success() {
-        let output: Vec<LintOutput> =
-            serde_json::from_slice(&output.stdout).with_context(|| {
-                format!(
-                    "Failed to serialize the linting output from `sqlfluff`: {}",
-                    String::from_utf8_lossy(&output.stdout)
-                )
-            })?;
-
-        Ok(output
-            .into_iter()
-            .map(|lint| Diagnostic {
-                range: Range {
-                    start: Position {
-                        line: (lint.start_line - 1) as u32,
-                        character: (lint.start_column - 1) as u32,
-                    },
-                    end: Position {
-                        line: (lint.end_line - 1) as u32,
-                        character: (lint.end_column - 1) as u32,
-                    },
-                },
-                severity: Some(DiagnosticSeverity::WARNING),
-                source: Some("sqlfluff-lsp".to_string()),
-                message: lint.message,
-                ..Default::default()
-            })
-            .collect::<Vec<_>>())
-    } else {
-        anyhow::bail!("`sqlfluff lint` failed: {output:?}")
+/// Splits a `sqlfluff` `github-annotation` message of the form `"LT09: ..."`
+/// into the leading rule code and the remaining free-text message.
+fn split_rule_code(message: &str) -> (Option<String>, String) {
+    match message.split_once(": ") {
+        Some((code, rest))
+            if !code.is_empty()
+                && code.len() <= 6
+                && code.chars().next().is_some_and(|c| c.is_ascii_uppercase())
+                && code.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            (Some(code.to_string()), rest.to_string())
+        }
+        _ => (None, message.to_string()),
     }
 }
 
-pub async fn fmt(
-    uri: &Uri,
-    content: &str,
-    dialect: Option<String>,
-) -> anyhow::Result<Vec<TextEdit>> {
-    let output = Sqlfluff::new("fix")
-        .dialect(dialect)
-        .args(&[
-            &format!("--stdin-filename={}", uri.path()),
-            "--disable-progress-bar",
-            "--nocolor",
-            "--quiet",
-            "-",
-        ])
-        .execute(content)
-        .await?;
-
-    let formatted_output = match output.status.code() {
-        Some(0 | 1) => String::from_utf8_lossy(&output.stdout).into_owned(),
-        _ => anyhow::bail!("`sqlfluff fix` failed: {output:?}"),
+/// Maps a rule code to its default severity: parse/templating failures
+/// (`PRS`/`TMP`, or no rule code at all if `sqlfluff` ever omits one) are
+/// errors, layout nits (`LT`) are informational, and everything else
+/// (convention rules such as `CP`/`CV`, ambiguity rules like `AM`, etc.) is a
+/// warning.
+fn default_severity(code: Option<&str>) -> DiagnosticSeverity {
+    let Some(code) = code else {
+        return DiagnosticSeverity::ERROR;
     };
 
-    let (mut line_count, mut last_line_len) = (0, 0);
-    let mut lines = content.lines().peekable();
-    while let Some(line) = lines.next() {
-        line_count += 1;
-        if lines.peek().is_none() {
-            last_line_len = line.encode_utf16().count() as u32;
+    match code {
+        "PRS" | "TMP" => DiagnosticSeverity::ERROR,
+        _ => match &code[..code.len().min(2)] {
+            "LT" => DiagnosticSeverity::INFORMATION,
+            _ => DiagnosticSeverity::WARNING,
+        },
+    }
+}
+
+/// Resolves a diagnostic's severity (client override, falling back to
+/// [`default_severity`]) and whether it should carry `DiagnosticTag::UNNECESSARY`.
+fn classify(
+    code: Option<&str>,
+    message: &str,
+    overrides: &HashMap<String, DiagnosticSeverity>,
+) -> (DiagnosticSeverity, Option<Vec<DiagnosticTag>>) {
+    let severity = code
+        .and_then(|code| overrides.get(code).copied())
+        .unwrap_or_else(|| default_severity(code));
+
+    let tags = message
+        .to_ascii_lowercase()
+        .contains("unnecessary")
+        .then(|| vec![DiagnosticTag::UNNECESSARY]);
+
+    (severity, tags)
+}
+
+/// A maximal contiguous block of lines that differ between the old and new
+/// document, expressed as half-open ranges into the (already prefix/suffix
+/// trimmed) line slices passed to [`diff_hunks`].
+struct Hunk {
+    old_start: usize,
+    old_end: usize,
+    new_start: usize,
+    new_end: usize,
+}
+
+/// Finds the line-level longest common subsequence between `old` and `new`
+/// via a textbook DP table, then walks it to collect the maximal contiguous
+/// change blocks (as opposed to a single span covering everything between
+/// the first and last differing line).
+fn diff_hunks(old: &[&str], new: &[&str]) -> Vec<Hunk> {
+    let (n, m) = (old.len(), new.len());
+    let mut lcs_len = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs_len[i][j] = if old[i] == new[j] {
+                lcs_len[i + 1][j + 1] + 1
+            } else {
+                lcs_len[i + 1][j].max(lcs_len[i][j + 1])
+            };
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let mut current: Option<Hunk> = None;
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            if let Some(hunk) = current.take() {
+                hunks.push(hunk);
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        let hunk = current.get_or_insert(Hunk {
+            old_start: i,
+            old_end: i,
+            new_start: j,
+            new_end: j,
+        });
+        if lcs_len[i + 1][j] >= lcs_len[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
         }
+        hunk.old_end = i;
+        hunk.new_end = j;
     }
 
-    // TODO(optimization) send only the necessary edits
-    Ok(vec![TextEdit::new(
-        Range::new(
-            Position::new(0, 0),
-            Position::new(line_count, last_line_len),
-        ),
-        formatted_output,
-    )])
+    if i < n || j < m {
+        let hunk = current.get_or_insert(Hunk {
+            old_start: i,
+            old_end: i,
+            new_start: j,
+            new_end: j,
+        });
+        hunk.old_end = n;
+        hunk.new_end = m;
+    }
+
+    if let Some(hunk) = current {
+        hunks.push(hunk);
+    }
+
+    hunks
 }
 
-struct Sqlfluff {
-    cmd: Command,
+/// The LSP position of `old_lines[line_idx]`'s first column, or (when
+/// `line_idx == old_lines.len()`) the position of the end of the document —
+/// which is the start of a virtual empty final line when the document ends
+/// with a newline, or the end of the last line's content otherwise.
+fn position_at(old_lines: &[&str], content_ends_with_newline: bool, line_idx: usize) -> Position {
+    if line_idx < old_lines.len() {
+        return Position::new(line_idx as u32, 0);
+    }
+
+    if content_ends_with_newline {
+        return Position::new(old_lines.len() as u32, 0);
+    }
+
+    let last_line = old_lines.last().copied().unwrap_or("");
+    Position::new(
+        old_lines.len().saturating_sub(1) as u32,
+        last_line.encode_utf16().count() as u32,
+    )
 }
 
-impl Sqlfluff {
-    fn new(command: &str) -> Self {
-        let mut cmd = Command::new("sqlfluff");
-        cmd.arg(command);
-        Sqlfluff { cmd }
+/// Diffs `content` against `formatted_output` line-by-line and returns the
+/// minimal set of `TextEdit`s that turn one into the other, instead of
+/// replacing the whole document: one edit per contiguous changed block, so
+/// that two unrelated fixes far apart in a large file don't bundle the
+/// untouched lines between them into a single giant edit. Columns are
+/// measured in UTF-16 code units to stay LSP-correct for non-ASCII SQL string
+/// literals.
+fn line_diff_edits(content: &str, formatted_output: &str) -> Vec<TextEdit> {
+    let old_lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = formatted_output.split_inclusive('\n').collect();
+    let ends_with_newline = content.ends_with('\n');
+
+    let max_common = old_lines.len().min(new_lines.len());
+    let mut prefix = 0;
+    while prefix < max_common && old_lines[prefix] == new_lines[prefix] {
+        prefix += 1;
     }
-    fn dialect(mut self, dialect: Option<String>) -> Self {
-        if let Some(d) = dialect {
-            self.cmd.arg(format!("--dialect={d}"));
+
+    let max_suffix = max_common - prefix;
+    let mut suffix = 0;
+    while suffix < max_suffix
+        && old_lines[old_lines.len() - 1 - suffix] == new_lines[new_lines.len() - 1 - suffix]
+    {
+        suffix += 1;
+    }
+
+    let old_mid = &old_lines[prefix..old_lines.len() - suffix];
+    let new_mid = &new_lines[prefix..new_lines.len() - suffix];
+
+    diff_hunks(old_mid, new_mid)
+        .into_iter()
+        .map(|hunk| {
+            let start = position_at(&old_lines, ends_with_newline, prefix + hunk.old_start);
+            let end = position_at(&old_lines, ends_with_newline, prefix + hunk.old_end);
+            let new_text = new_lines[prefix + hunk.new_start..prefix + hunk.new_end].concat();
+            TextEdit::new(Range::new(start, end), new_text)
+        })
+        .collect()
+}
+
+/// Source of the persistent worker daemon, embedded in the binary and
+/// materialized to disk at spawn time: a tiny Python script that imports
+/// `sqlfluff`'s own public API (`sqlfluff.lint`/`sqlfluff.fix`) once and then
+/// serves requests over stdin/stdout, so rule-loading and interpreter
+/// startup happen once per `Config` instead of once per keystroke.
+const WORKER_SCRIPT: &str = include_str!("../scripts/sqlfluff_worker.py");
+
+/// Writes [`WORKER_SCRIPT`] to a stable path in the system temp directory and
+/// returns it, so `spawn_child` has something to hand to `python3`.
+fn worker_script_path() -> std::io::Result<PathBuf> {
+    let path = std::env::temp_dir().join("sqlfluff-lsp-worker.py");
+    std::fs::write(&path, WORKER_SCRIPT)?;
+    Ok(path)
+}
+
+/// Resolves the Python interpreter to run [`WORKER_SCRIPT`] with: the
+/// `python3` next to `config.sqlfluff_path` if there is one (the common case
+/// of both living in the same virtualenv's `bin/`), otherwise whatever
+/// `python3` is on `PATH`.
+fn python_interpreter(config: &Config) -> PathBuf {
+    if let Some(sqlfluff_path) = &config.sqlfluff_path {
+        if let Some(sibling) = Path::new(sqlfluff_path).parent().map(|dir| dir.join("python3")) {
+            if sibling.is_file() {
+                return sibling;
+            }
         }
-        self
     }
-    fn args(mut self, args: &[&str]) -> Self {
-        self.cmd.args(args);
-        self
+
+    PathBuf::from("python3")
+}
+
+type RequestId = u64;
+
+#[derive(Debug, Serialize)]
+struct WorkerRequest<'a> {
+    id: RequestId,
+    command: &'a str,
+    content: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rules: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkerResponse {
+    id: RequestId,
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LintResult {
+    diagnostics: Vec<LintOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixResult {
+    formatted: String,
+}
+
+type PendingRequests = Arc<Mutex<HashMap<RequestId, oneshot::Sender<anyhow::Result<serde_json::Value>>>>>;
+
+/// A long-lived `sqlfluff_worker.py` subprocess that lint/fmt requests are
+/// multiplexed onto, so that each `did_change` doesn't pay a fresh
+/// interpreter and rule-loading startup cost. This trades away per-file path
+/// context (`sqlfluff`'s simple Python API has no `--stdin-filename`
+/// equivalent, so e.g. dbt project-root discovery relative to the edited
+/// file isn't available) in exchange for not forking a process per
+/// keystroke.
+#[derive(Debug)]
+pub struct Worker {
+    config: Config,
+    next_id: AtomicU64,
+    pending: PendingRequests,
+    stdin: Mutex<ChildStdin>,
+    child: Mutex<Child>,
+    shutting_down: AtomicBool,
+}
+
+impl Worker {
+    /// Spawns the worker subprocess and starts the background task that
+    /// reads its responses (and restarts it if it ever exits).
+    pub fn spawn(config: Config) -> Arc<Self> {
+        let (child, stdin) = Self::spawn_child(&config)
+            .unwrap_or_else(|error| panic!("failed to spawn sqlfluff worker: {error}"));
+
+        let worker = Arc::new(Worker {
+            config,
+            next_id: AtomicU64::new(0),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            stdin: Mutex::new(stdin),
+            child: Mutex::new(child),
+            shutting_down: AtomicBool::new(false),
+        });
+
+        tokio::spawn(Self::drive(Arc::clone(&worker)));
+
+        worker
+    }
+
+    /// Kills the subprocess and stops the driving task for good, for a
+    /// deliberate (as opposed to crash-triggered) shutdown.
+    pub async fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let _ = self.child.lock().await.start_kill();
     }
-    async fn execute(mut self, content: &str) -> io::Result<std::process::Output> {
-        let mut child = self
-            .cmd
+
+    fn spawn_child(config: &Config) -> std::io::Result<(Child, ChildStdin)> {
+        let script = worker_script_path()?;
+
+        let mut cmd = Command::new(python_interpreter(config));
+        cmd.arg(script);
+        if let Some(dialect) = &config.dialect {
+            cmd.arg(format!("--dialect={dialect}"));
+        }
+        if let Some(templater) = &config.templater {
+            cmd.arg(format!("--templater={templater}"));
+        }
+
+        let mut child = cmd
             .kill_on_drop(true)
-            .stdout(Stdio::piped())
             .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()?;
 
-        {
-            let mut stdin = child
-                .stdin
+        let stdin = child
+            .stdin
+            .take()
+            .expect("child should have a handle to stdin");
+        let stderr = child
+            .stderr
+            .take()
+            .expect("child should have a handle to stderr");
+        tokio::spawn(Self::drain_stderr(stderr));
+
+        Ok((child, stdin))
+    }
+
+    /// Drains the worker's stderr and forwards each line to `tracing`, so
+    /// Python warnings/tracebacks are visible for debugging and, more
+    /// importantly, so the pipe never fills up and blocks the worker's
+    /// writes to it mid-request.
+    async fn drain_stderr(stderr: tokio::process::ChildStderr) {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            error!("sqlfluff worker stderr: {line}");
+        }
+    }
+
+    /// Reads framed responses off the worker's stdout and resolves the
+    /// matching pending request; restarts the subprocess if it exits, unless
+    /// the exit was requested via [`Worker::shutdown`].
+    async fn drive(worker: Arc<Self>) {
+        loop {
+            let stdout = worker
+                .child
+                .lock()
+                .await
+                .stdout
                 .take()
-                .expect("child should have a handle to stdin");
-            stdin.write_all(content.as_bytes()).await?;
+                .expect("child should have a handle to stdout");
+            let mut lines = BufReader::new(stdout).lines();
+
+            loop {
+                match lines.next_line().await {
+                    Ok(Some(line)) => match serde_json::from_str::<WorkerResponse>(&line) {
+                        Ok(response) => {
+                            if let Some(tx) = worker.pending.lock().await.remove(&response.id) {
+                                let _ = tx.send(match response.error {
+                                    Some(error) => Err(anyhow::anyhow!(error)),
+                                    None => Ok(response.result.unwrap_or(serde_json::Value::Null)),
+                                });
+                            }
+                        }
+                        Err(error) => error!("failed to parse sqlfluff worker response: {error}"),
+                    },
+                    Ok(None) => break,
+                    Err(error) => {
+                        error!("failed to read from sqlfluff worker: {error}");
+                        break;
+                    }
+                }
+            }
+
+            let shutting_down = worker.shutting_down.load(Ordering::SeqCst);
+            let message = if shutting_down {
+                "sqlfluff worker shut down"
+            } else {
+                "sqlfluff worker restarted"
+            };
+            for (_, tx) in worker.pending.lock().await.drain() {
+                let _ = tx.send(Err(anyhow::anyhow!(message)));
+            }
+
+            if shutting_down {
+                return;
+            }
+
+            error!("sqlfluff worker exited, restarting");
+            loop {
+                match Self::spawn_child(&worker.config) {
+                    Ok((child, stdin)) => {
+                        *worker.stdin.lock().await = stdin;
+                        *worker.child.lock().await = child;
+                        break;
+                    }
+                    Err(error) => {
+                        error!("failed to restart sqlfluff worker: {error}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
         }
+    }
+
+    async fn request(
+        &self,
+        command: &str,
+        content: &str,
+        rules: Option<&str>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(id, tx);
+
+        let request = WorkerRequest {
+            id,
+            command,
+            content,
+            rules,
+        };
+        let mut line = serde_json::to_string(&request)?;
+        line.push('\n');
+
+        if let Err(error) = self.stdin.lock().await.write_all(line.as_bytes()).await {
+            self.pending.lock().await.remove(&id);
+            return Err(error.into());
+        }
+
+        rx.await.context("sqlfluff worker closed before responding")?
+    }
+
+    pub async fn lint(
+        &self,
+        _uri: &Uri,
+        content: &str,
+        rule_severity_overrides: &HashMap<String, DiagnosticSeverity>,
+    ) -> anyhow::Result<Vec<Diagnostic>> {
+        let result: LintResult =
+            serde_json::from_value(self.request("lint", content, None).await?)?;
 
-        child.wait_with_output().await
+        Ok(result
+            .diagnostics
+            .into_iter()
+            .map(|lint| {
+                let (code, message) = split_rule_code(&lint.message);
+                let (severity, tags) =
+                    classify(code.as_deref(), &message, rule_severity_overrides);
+                Diagnostic {
+                    range: Range {
+                        start: Position {
+                            line: (lint.start_line - 1) as u32,
+                            character: (lint.start_column - 1) as u32,
+                        },
+                        end: Position {
+                            line: (lint.end_line - 1) as u32,
+                            character: (lint.end_column - 1) as u32,
+                        },
+                    },
+                    severity: Some(severity),
+                    tags,
+                    code: code.map(NumberOrString::String),
+                    source: Some("sqlfluff-lsp".to_string()),
+                    message,
+                    ..Default::default()
+                }
+            })
+            .collect())
+    }
+
+    pub async fn fmt(&self, uri: &Uri, content: &str) -> anyhow::Result<Vec<TextEdit>> {
+        self.fix(uri, content, None).await
+    }
+
+    /// Runs a fix, optionally scoped to a single comma-separated list of rule
+    /// codes (e.g. `Some("LT09")`), and diffs the result against `content` to
+    /// produce the minimal set of `TextEdit`s.
+    pub async fn fix(
+        &self,
+        _uri: &Uri,
+        content: &str,
+        rules: Option<&str>,
+    ) -> anyhow::Result<Vec<TextEdit>> {
+        let result: FixResult =
+            serde_json::from_value(self.request("fix", content, rules).await?)?;
+
+        Ok(line_diff_edits(content, &result.formatted))
     }
 }
 
@@ -152,10 +528,87 @@ impl Sqlfluff {
 mod tests {
     use super::*;
 
-    use std::fs::File;
-    use std::io::Write;
     use std::str::FromStr as _;
-    use tempfile::tempdir;
+
+    fn test_uri() -> Uri {
+        Uri::from_str("file:///tmp/temp.sql").unwrap()
+    }
+
+    fn snowflake_config() -> Config {
+        Config {
+            dialect: Some("snowflake".to_string()),
+            templater: None,
+            sqlfluff_path: None,
+            rule_severity_overrides: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_default_severity_parse_and_templating_errors() {
+        assert_eq!(default_severity(Some("PRS")), DiagnosticSeverity::ERROR);
+        assert_eq!(default_severity(Some("TMP")), DiagnosticSeverity::ERROR);
+        assert_eq!(default_severity(None), DiagnosticSeverity::ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_lint_parse_error() {
+        // Unparsable SQL: `sqlfluff` reports this as a `PRS` violation, which
+        // must be classified as `ERROR` rather than falling into the
+        // catch-all `WARNING` arm used for ordinary lint rules.
+        let sql_file_content = "SELECT * FROM WHERE;";
+
+        let worker = Worker::spawn(snowflake_config());
+        let diagnostics = worker
+            .lint(&test_uri(), sql_file_content, &HashMap::new())
+            .await
+            .unwrap();
+
+        let parse_error = diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.code == Some(NumberOrString::String("PRS".to_string())))
+            .expect("expected a PRS parse-failure diagnostic");
+        assert_eq!(parse_error.severity, Some(DiagnosticSeverity::ERROR));
+    }
+
+    #[test]
+    fn test_line_diff_edits_trailing_newline() {
+        // The last line changes and the file ends with a newline: the edit
+        // must consume that trailing newline rather than leaving it to
+        // duplicate alongside the one in `new_text`.
+        let edits = line_diff_edits("a\nb\n", "a\nc\n");
+
+        assert_eq!(
+            edits,
+            vec![TextEdit::new(
+                Range::new(Position::new(1, 0), Position::new(2, 0)),
+                "c\n".to_string(),
+            )]
+        );
+    }
+
+    #[test]
+    fn test_line_diff_edits_multiple_hunks() {
+        // Two unrelated single-line fixes far apart in the file should
+        // produce two separate edits, leaving the untouched middle alone.
+        let old = "a\nb\nc\nd\ne\n";
+        let new = "a\nB\nc\nd\nE\n";
+
+        let edits = line_diff_edits(old, new);
+
+        assert_eq!(
+            edits,
+            vec![
+                TextEdit::new(
+                    Range::new(Position::new(1, 0), Position::new(2, 0)),
+                    "B\n".to_string(),
+                ),
+                TextEdit::new(
+                    Range::new(Position::new(4, 0), Position::new(5, 0)),
+                    "E\n".to_string(),
+                ),
+            ]
+        );
+    }
 
     #[tokio::test]
     async fn test_fmt_simple() {
@@ -167,45 +620,53 @@ INNER JOIN sales ON customer.customer_id = sales.customer_id
  GROUP BY region
 ORDER BY total_sales desc
         ";
-        let expected_text_edit = TextEdit {
-            range: Range {
-                start: Position {
-                    line: 0,
-                    character: 0,
-                },
-                end: Position {
-                    line: 7,
-                    character: 8,
+        // The "SELECT" and "INNER JOIN" lines are untouched, so the rewrite
+        // splits into two edits: lines 1-2, and line 4 through the end of
+        // document.
+        let expected_text_edits = vec![
+            TextEdit {
+                range: Range {
+                    start: Position {
+                        line: 1,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: 3,
+                        character: 0,
+                    },
                 },
-            },
-            new_text: "\
-SELECT
+                new_text: "\
     region,
     COUNT(*) AS total_customers,
     SUM(amount) AS total_sales
 FROM customer
-INNER JOIN sales ON customer.customer_id = sales.customer_id
+"
+                .to_string(),
+            },
+            TextEdit {
+                range: Range {
+                    start: Position {
+                        line: 4,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: 6,
+                        character: 8,
+                    },
+                },
+                new_text: "\
 GROUP BY region
 ORDER BY total_sales DESC
 "
-            .to_string(),
-        };
+                .to_string(),
+            },
+        ];
 
-        let tmp_dir = tempdir().unwrap();
-        let file_path = tmp_dir.path().join("temp.sql");
-        let mut tmp_file = File::create(&file_path).unwrap();
-        writeln!(tmp_file, "{sql_file_content}").unwrap();
-
-        let text_edits = fmt(
-            &Uri::from_str(&file_path.as_os_str().to_string_lossy()).unwrap(),
-            sql_file_content,
-            Some("snowflake".to_string()),
-        )
-        .await
-        .unwrap();
-
-        assert!(text_edits.len() == 1);
-        assert_eq!(text_edits[0], expected_text_edit);
+        let worker = Worker::spawn(snowflake_config());
+        let text_edits = worker.fmt(&test_uri(), sql_file_content).await.unwrap();
+
+        assert!(text_edits.len() == 2);
+        assert_eq!(text_edits, expected_text_edits);
     }
 
     #[tokio::test]
@@ -216,18 +677,11 @@ SELECT
 FROm customer
             ";
 
-        let tmp_dir = tempdir().unwrap();
-        let file_path = tmp_dir.path().join("temp.sql");
-        let mut tmp_file = File::create(&file_path).unwrap();
-        writeln!(tmp_file, "{sql_file_content}").unwrap();
-
-        let diagnostics = lint(
-            &Uri::from_str(&file_path.as_os_str().to_string_lossy()).unwrap(),
-            sql_file_content,
-            Some("snowflake".to_string()),
-        )
-        .await
-        .unwrap();
+        let worker = Worker::spawn(snowflake_config());
+        let diagnostics = worker
+            .lint(&test_uri(), sql_file_content, &HashMap::new())
+            .await
+            .unwrap();
 
         let expected_diagnostics = [
             Diagnostic {
@@ -241,9 +695,10 @@ FROm customer
                         character: 67,
                     },
                 },
-                severity: Some(DiagnosticSeverity::WARNING),
+                severity: Some(DiagnosticSeverity::INFORMATION),
+                code: Some(NumberOrString::String("LT09".to_string())),
                 source: Some("sqlfluff-lsp".to_string()),
-                message: "LT09: Select targets should be on a new line unless there is only one select target.".to_string(),
+                message: "Select targets should be on a new line unless there is only one select target.".to_string(),
                 ..Default::default()
             },
             Diagnostic {
@@ -258,8 +713,9 @@ FROm customer
                     },
                 },
                 severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String("CP01".to_string())),
                 source: Some("sqlfluff-lsp".to_string()),
-                message: "CP01: Keywords must be consistently upper case.".to_string(),
+                message: "Keywords must be consistently upper case.".to_string(),
                 ..Default::default()
             },
             Diagnostic {
@@ -273,9 +729,11 @@ FROm customer
                         character: 12,
                     },
                 },
-                severity: Some(DiagnosticSeverity::WARNING),
+                severity: Some(DiagnosticSeverity::INFORMATION),
+                tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                code: Some(NumberOrString::String("LT01".to_string())),
                 source: Some("sqlfluff-lsp".to_string()),
-                message: "LT01: Unnecessary trailing whitespace at end of file.".to_string(),
+                message: "Unnecessary trailing whitespace at end of file.".to_string(),
                 ..Default::default()
             },
         ];